@@ -13,9 +13,17 @@ pub struct GloneOptions {
     pub config: Option<Config>,
 }
 
+fn default_concurrency() -> usize {
+    std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
     providers: Vec<Provider>,
+    #[serde(default = "default_concurrency")]
+    pub concurrency: usize,
 }
 
 impl Config {
@@ -24,21 +32,41 @@ impl Config {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Provider {
     pub name: String,
     pub url: String,
     pub branch: String,
     pub sync_dir: String,
     pub auth: Auth,
+    #[serde(default)]
+    pub submodules: bool,
+    #[serde(default)]
+    pub strategy: MergeStrategy,
+    #[serde(default)]
+    pub push: bool,
+    #[serde(default)]
+    pub depth: Option<u32>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub enum MergeStrategy {
+    #[serde(rename = "ff-only")]
+    FfOnly,
+    #[serde(rename = "merge")]
+    #[default]
+    Merge,
+    #[serde(rename = "rebase")]
+    Rebase,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Auth {
     pub r#type: AuthType,
     pub username: Option<String>,
     pub password: Option<String>,
     pub path: Option<String>,
+    pub passphrase: Option<String>,
 }
 
 impl Auth {
@@ -55,12 +83,22 @@ impl Auth {
         env::var(self.password.as_ref().unwrap()).unwrap()
     }
 
+    /// Resolves the `_`-prefixed env var name in `passphrase`, the same way
+    /// `get_username`/`get_password` do. Returns `None` if no passphrase is
+    /// configured or the env var isn't set (e.g. an unencrypted key).
+    pub fn get_passphrase(&self) -> Option<String> {
+        self.passphrase
+            .as_ref()
+            .filter(|p| p.starts_with('_'))
+            .and_then(|p| env::var(p).ok())
+    }
+
     pub fn get_ssh_path(&self) -> &String {
         self.path.as_ref().unwrap()
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum AuthType {
     #[serde(rename = "token")]
     Token,
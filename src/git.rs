@@ -1,48 +1,195 @@
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use std::cell::RefCell;
 use std::path::Path;
+use std::rc::Rc;
 
-use git2::{Cred, RemoteCallbacks, Repository};
+use git2::{Cred, CredentialType, RemoteCallbacks, Repository};
 
-use crate::config::{AuthType, Provider};
+use crate::config::{AuthType, MergeStrategy, Provider};
 
-pub fn download(provider: &Provider, m: &MultiProgress) {
+/// A single commit pulled in by a sync, as shown in the post-sync changelog.
+pub struct CommitSummary {
+    pub id: String,
+    pub author: String,
+    pub summary: String,
+}
+
+/// Outcome of syncing a single provider, reported back to the caller instead
+/// of panicking so a bounded worker pool can keep going after a failure.
+pub enum SyncOutcome {
+    Cloned,
+    UpToDate,
+    Updated(Vec<CommitSummary>),
+    Diverged,
+    Failed(git2::Error),
+}
+
+pub fn download(provider: &Provider, m: &MultiProgress) -> SyncOutcome {
     let repo_path = Path::new(&provider.sync_dir);
 
-    if repo_path.exists() && repo_path.is_dir() {
-        pull(provider, repo_path, &m);
+    let result = if repo_path.exists() && repo_path.is_dir() {
+        pull(provider, repo_path, m)
     } else {
-        clone(provider, repo_path, &m);
+        clone(provider, repo_path, m)
+    };
+
+    match result {
+        Ok(outcome) => outcome,
+        Err(e) => SyncOutcome::Failed(e),
     }
 }
 
-fn pull(provider: &Provider, repo_path: &Path, m: &MultiProgress) {
-    let repo = match Repository::open(repo_path) {
-        Ok(repo) => repo,
-        Err(e) => {
-            log::error!("Failed to open the repository {:?} {}", repo_path, e);
-            panic!("Failed to open the repository {:?} {}", repo_path, e)
-        }
+fn pull(
+    provider: &Provider,
+    repo_path: &Path,
+    m: &MultiProgress,
+) -> Result<SyncOutcome, git2::Error> {
+    let repo = Repository::open(repo_path).map_err(|e| {
+        log::error!("Failed to open the repository {:?} {}", repo_path, e);
+        e
+    })?;
+
+    if provider.push {
+        commit_local_changes(&repo)?;
+        push_branch(&repo, provider)?;
+    }
+
+    let old_oid = repo.head().ok().and_then(|h| h.target());
+
+    let mut remote = repo.find_remote("origin")?;
+    let fetch_commit = fetch(&repo, &[&provider.branch], &mut remote, m, provider.depth)?;
+
+    let diverged = merge(&repo, provider, fetch_commit)?;
+    update_submodules(&repo, provider, m)?;
+
+    if diverged {
+        return Ok(SyncOutcome::Diverged);
+    }
+
+    let new_oid = repo.head()?.target();
+
+    let commits = match (old_oid, new_oid) {
+        (Some(old), Some(new)) if old != new => commits_between(&repo, old, new)?,
+        _ => Vec::new(),
     };
 
-    let mut remote = repo.find_remote("origin").unwrap();
-    let fetch_commit = fetch(&repo, &[&provider.branch], &mut remote, &m).unwrap();
+    if commits.is_empty() {
+        Ok(SyncOutcome::UpToDate)
+    } else {
+        Ok(SyncOutcome::Updated(commits))
+    }
+}
 
-    let _ = merge(&repo, &provider.branch, fetch_commit);
+/// Walks the commits reachable from `new` but not from `old`, newest first,
+/// for the post-sync changelog.
+fn commits_between(
+    repo: &Repository,
+    old: git2::Oid,
+    new: git2::Oid,
+) -> Result<Vec<CommitSummary>, git2::Error> {
+    let mut revwalk = repo.revwalk()?;
+    revwalk.push(new)?;
+    revwalk.hide(old)?;
+
+    revwalk
+        .map(|oid| {
+            let oid = oid?;
+            let commit = repo.find_commit(oid)?;
+            let summary = CommitSummary {
+                id: oid.to_string()[..7].to_string(),
+                author: commit.author().name().unwrap_or("unknown").to_string(),
+                summary: commit.summary().unwrap_or("").to_string(),
+            };
+            Ok(summary)
+        })
+        .collect()
 }
 
-fn clone(provider: &Provider, repo_path: &Path, m: &MultiProgress) {
-    let mut callbacks = RemoteCallbacks::new();
+/// Stages and commits any dirty working tree changes so they're carried
+/// along by the push below. Only ever called for providers with `push`
+/// explicitly enabled, so pull-only providers are never mutated. Refuses to
+/// run if the index still has unresolved conflicts from a previous sync, so
+/// conflict markers never get committed and pushed to `origin`.
+fn commit_local_changes(repo: &Repository) -> Result<(), git2::Error> {
+    let mut index = repo.index()?;
+    if index.has_conflicts() {
+        return Err(git2::Error::from_str(
+            "Working tree has unresolved merge conflicts; refusing to commit and push them",
+        ));
+    }
 
-    let sty = ProgressStyle::with_template(
-        "[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}",
-    )
-    .unwrap()
-    .progress_chars("##-");
+    let mut status_opts = git2::StatusOptions::new();
+    status_opts.include_untracked(true);
 
-    let n = 100;
-    let pb = m.add(ProgressBar::new(n));
-    pb.set_style(sty.clone());
-    pb.set_message(format!("{}", &provider.name));
+    if repo.statuses(Some(&mut status_opts))?.is_empty() {
+        return Ok(());
+    }
+
+    index.add_all(["*"].iter(), git2::IndexAddOption::DEFAULT, None)?;
+    index.write()?;
+
+    let tree = repo.find_tree(index.write_tree()?)?;
+    let sig = repo.signature()?;
+    let head_commit = repo.head()?.peel_to_commit()?;
+
+    log::info!("Committing local changes before push");
+    repo.commit(
+        Some("HEAD"),
+        &sig,
+        &sig,
+        "glone: snapshot local changes",
+        &tree,
+        &[&head_commit],
+    )?;
+
+    Ok(())
+}
+
+/// Pushes the configured branch back to `origin`, reusing the same
+/// token/SSH credentials used for cloning. A non-fast-forward rejection is
+/// surfaced as an error rather than silently dropped.
+fn push_branch(repo: &Repository, provider: &Provider) -> Result<(), git2::Error> {
+    let mut remote = repo.find_remote("origin")?;
+    let mut callbacks = credential_callbacks(provider);
+
+    let rejected = Rc::new(RefCell::new(None));
+    let rejected_cb = Rc::clone(&rejected);
+    callbacks.push_update_reference(move |_refname, status| {
+        if let Some(message) = status {
+            *rejected_cb.borrow_mut() = Some(message.to_string());
+        }
+        Ok(())
+    });
+
+    let mut push_opts = git2::PushOptions::new();
+    push_opts.remote_callbacks(callbacks);
+
+    let refspec = format!("refs/heads/{0}:refs/heads/{0}", provider.branch);
+    log::info!("Pushing {} to origin", provider.branch);
+    remote.push(&[&refspec], Some(&mut push_opts))?;
+
+    let rejected = rejected.borrow_mut().take();
+    match rejected {
+        Some(message) => Err(git2::Error::from_str(&format!(
+            "Push of {} rejected: {}",
+            provider.branch, message
+        ))),
+        None => Ok(()),
+    }
+}
+
+fn progress_style() -> ProgressStyle {
+    ProgressStyle::with_template("[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}")
+        .unwrap()
+        .progress_chars("##-")
+}
+
+/// Builds the credential callbacks for `provider`'s configured auth type.
+/// Shared between the top-level clone, the push of local changes and
+/// submodule updates so private submodules authenticate the same way as their
+/// parent repository. The fetch in `pull` does not use these callbacks.
+fn credential_callbacks(provider: &Provider) -> RemoteCallbacks<'_> {
+    let mut callbacks = RemoteCallbacks::new();
 
     match provider.auth.r#type {
         AuthType::Token => {
@@ -56,18 +203,60 @@ fn clone(provider: &Provider, repo_path: &Path, m: &MultiProgress) {
             }
         }
         AuthType::Ssh => {
-            callbacks.credentials(|_url, username_from_url, _allowed_types| {
-                Cred::ssh_key(
-                    username_from_url.unwrap(),
-                    None,
-                    Path::new(provider.auth.get_ssh_path()),
-                    None,
-                )
+            let mut tried_agent = false;
+            let mut tried_key = false;
+
+            callbacks.credentials(move |_url, username_from_url, allowed_types| {
+                if !allowed_types.contains(CredentialType::SSH_KEY) {
+                    return Err(git2::Error::from_str(
+                        "Remote does not accept SSH key authentication",
+                    ));
+                }
+
+                let username = username_from_url.unwrap_or("git");
+
+                if provider.auth.path.is_none() && !tried_agent {
+                    tried_agent = true;
+                    if let Ok(cred) = Cred::ssh_key_from_agent(username) {
+                        return Ok(cred);
+                    }
+                }
+
+                if !tried_key {
+                    tried_key = true;
+                    if provider.auth.path.is_some() {
+                        return Cred::ssh_key(
+                            username,
+                            None,
+                            Path::new(provider.auth.get_ssh_path()),
+                            provider.auth.get_passphrase().as_deref(),
+                        );
+                    }
+                }
+
+                Err(git2::Error::from_str(&format!(
+                    "No usable SSH identity for {} (tried the configured key, or ssh-agent when no key path is set)",
+                    username
+                )))
             });
         }
         AuthType::Public => {}
     }
 
+    callbacks
+}
+
+fn clone(
+    provider: &Provider,
+    repo_path: &Path,
+    m: &MultiProgress,
+) -> Result<SyncOutcome, git2::Error> {
+    let mut callbacks = credential_callbacks(provider);
+
+    let pb = m.add(ProgressBar::new(100));
+    pb.set_style(progress_style());
+    pb.set_message(format!("{}", &provider.name));
+
     callbacks.transfer_progress(move |stats| {
         let total = stats.total_objects().try_into().unwrap();
         let received: u64 = stats.received_objects().try_into().unwrap();
@@ -86,14 +275,90 @@ fn clone(provider: &Provider, repo_path: &Path, m: &MultiProgress) {
     let mut fo = git2::FetchOptions::new();
     fo.remote_callbacks(callbacks);
 
+    if let Some(depth) = provider.depth {
+        fo.depth(depth.try_into().unwrap_or(i32::MAX));
+    }
+
     // Prepare builder.
     let mut builder = git2::build::RepoBuilder::new();
     builder.fetch_options(fo);
+    builder.branch(&provider.branch);
+
+    if provider.depth.is_some() {
+        // A depth implies single-branch: only fetch the configured branch
+        // ref instead of the full set of remote refs.
+        let branch = provider.branch.clone();
+        builder.remote_create(move |repo, name, url| {
+            let refspec = format!("+refs/heads/{0}:refs/remotes/{1}/{0}", branch, name);
+            repo.remote_with_fetch(name, url, &refspec)
+        });
+    }
 
     // Clone the project.
-    let _ = builder
-        .branch(&provider.branch)
-        .clone(&provider.url, repo_path);
+    let repo = builder.clone(&provider.url, repo_path)?;
+
+    update_submodules(&repo, provider, m)?;
+
+    Ok(SyncOutcome::Cloned)
+}
+
+fn update_submodules(
+    repo: &Repository,
+    provider: &Provider,
+    m: &MultiProgress,
+) -> Result<(), git2::Error> {
+    if !provider.submodules {
+        return Ok(());
+    }
+
+    update_submodules_recursive(repo, provider, m)
+}
+
+/// Recursively initializes and updates `repo`'s submodules, and their
+/// submodules in turn, so a submodule that itself depends on submodules
+/// comes out fully populated instead of just one level deep.
+fn update_submodules_recursive(
+    repo: &Repository,
+    provider: &Provider,
+    m: &MultiProgress,
+) -> Result<(), git2::Error> {
+    for mut submodule in repo.submodules()? {
+        let name = submodule.name().unwrap_or("<submodule>").to_string();
+
+        let mut callbacks = credential_callbacks(provider);
+
+        let pb = m.add(ProgressBar::new(100));
+        pb.set_style(progress_style());
+        pb.set_message(format!("  {}", name));
+
+        callbacks.transfer_progress(move |stats| {
+            let total = stats.total_objects().try_into().unwrap();
+            let received: u64 = stats.received_objects().try_into().unwrap();
+            pb.set_length(total);
+
+            if received == total {
+                pb.finish_with_message("finished");
+            } else {
+                pb.inc(1);
+            }
+
+            true
+        });
+
+        let mut fo = git2::FetchOptions::new();
+        fo.remote_callbacks(callbacks);
+
+        let mut update_opts = git2::SubmoduleUpdateOptions::new();
+        update_opts.fetch(fo);
+
+        submodule.update(true, Some(&mut update_opts))?;
+
+        if let Ok(sub_repo) = submodule.open() {
+            update_submodules_recursive(&sub_repo, provider, m)?;
+        }
+    }
+
+    Ok(())
 }
 
 fn fetch<'a>(
@@ -101,6 +366,7 @@ fn fetch<'a>(
     refs: &[&str],
     remote: &'a mut git2::Remote,
     m: &MultiProgress,
+    depth: Option<u32>,
 ) -> Result<git2::AnnotatedCommit<'a>, git2::Error> {
     let mut cb = git2::RemoteCallbacks::new();
 
@@ -133,6 +399,13 @@ fn fetch<'a>(
     let mut fo = git2::FetchOptions::new();
     fo.remote_callbacks(cb);
 
+    if let Some(depth) = depth {
+        // A shallow repo can only ever deepen a fetch up to its existing
+        // depth, so a shallow fetch followed by the usual fast-forward in
+        // `merge` is the correct (and only sensible) pull path here.
+        fo.depth(depth.try_into().unwrap_or(i32::MAX));
+    }
+
     fo.download_tags(git2::AutotagOption::All);
     log::info!("Fetching {} for repo", remote.name().unwrap());
     remote.fetch(refs, Some(&mut fo), None)?;
@@ -158,11 +431,14 @@ fn fast_forward(
     Ok(())
 }
 
+/// Merges `remote` into `local`, returning `Ok(true)` if the trees conflicted
+/// and the merge was left for the operator to resolve by hand instead of
+/// being committed.
 fn normal_merge(
     repo: &Repository,
     local: &git2::AnnotatedCommit,
     remote: &git2::AnnotatedCommit,
-) -> Result<(), git2::Error> {
+) -> Result<bool, git2::Error> {
     let local_tree = repo.find_commit(local.id())?.tree()?;
     let remote_tree = repo.find_commit(remote.id())?.tree()?;
     let ancestor = repo
@@ -171,9 +447,9 @@ fn normal_merge(
     let mut idx = repo.merge_trees(&ancestor, &local_tree, &remote_tree, None)?;
 
     if idx.has_conflicts() {
-        println!("Merge conflicts detected...");
+        log::warn!("Merge conflicts detected; leaving them for the operator to resolve");
         repo.checkout_index(Some(&mut idx), None)?;
-        return Ok(());
+        return Ok(true);
     }
     let result_tree = repo.find_tree(idx.write_tree_to(repo)?)?;
     // now create the merge commit
@@ -192,14 +468,45 @@ fn normal_merge(
     )?;
     // Set working tree to match head.
     repo.checkout_head(None)?;
+    Ok(false)
+}
+
+fn rebase_onto(
+    repo: &Repository,
+    local: &git2::AnnotatedCommit,
+    upstream: &git2::AnnotatedCommit,
+) -> Result<(), git2::Error> {
+    let mut rebase = repo.rebase(Some(local), Some(upstream), None, None)?;
+    let sig = repo.signature()?;
+
+    while let Some(op) = rebase.next() {
+        op?;
+
+        if repo.index()?.has_conflicts() {
+            rebase.abort()?;
+            return Err(git2::Error::from_str(
+                "Rebase conflict detected; aborted to leave the working tree untouched",
+            ));
+        }
+
+        rebase.commit(None, &sig, None)?;
+    }
+
+    rebase.finish(None)?;
     Ok(())
 }
 
+/// Merges `fetch_commit` into the current branch according to `provider`'s
+/// configured strategy, returning `Ok(true)` if the branches diverged and
+/// nothing was committed (either because `ff-only` skipped a non-fast-forward
+/// update, or because a `merge` attempt hit conflicts) so the caller can
+/// report that distinctly instead of as `UpToDate`.
 fn merge<'a>(
     repo: &'a Repository,
-    remote_branch: &str,
+    provider: &Provider,
     fetch_commit: git2::AnnotatedCommit<'a>,
-) -> Result<(), git2::Error> {
+) -> Result<bool, git2::Error> {
+    let remote_branch = &provider.branch;
     let analysis = repo.merge_analysis(&[&fetch_commit])?;
 
     if analysis.0.is_fast_forward() {
@@ -228,11 +535,150 @@ fn merge<'a>(
                 ))?;
             }
         };
+        Ok(false)
     } else if analysis.0.is_normal() {
         let head_commit = repo.reference_to_annotated_commit(&repo.head()?)?;
-        normal_merge(&repo, &head_commit, &fetch_commit)?;
+
+        match provider.strategy {
+            MergeStrategy::FfOnly => {
+                log::warn!(
+                    "{} has diverged from {}; skipping (ff-only strategy)",
+                    provider.name,
+                    remote_branch
+                );
+                Ok(true)
+            }
+            MergeStrategy::Merge => normal_merge(repo, &head_commit, &fetch_commit),
+            MergeStrategy::Rebase => {
+                rebase_onto(repo, &head_commit, &fetch_commit)?;
+                Ok(false)
+            }
+        }
     } else {
         log::info!("There is nothing to do...");
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn temp_dir(label: &str) -> std::path::PathBuf {
+        static COUNTER: AtomicU32 = AtomicU32::new(0);
+        let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let dir =
+            std::env::temp_dir().join(format!("glone-test-{}-{}-{}", std::process::id(), label, n));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Writes `name` with `contents` in a non-bare repo's working tree and
+    /// commits it, advancing the current branch.
+    fn commit_file(repo: &Repository, name: &str, contents: &str, message: &str) -> git2::Oid {
+        std::fs::write(repo.workdir().unwrap().join(name), contents).unwrap();
+
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new(name)).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+
+        commit_tree(repo, tree, message)
+    }
+
+    /// Commits `tree` directly, for repos (including bare ones) where there
+    /// is no working tree to write into.
+    fn commit_tree(repo: &Repository, tree: git2::Tree, message: &str) -> git2::Oid {
+        let sig = git2::Signature::now("Test", "test@example.com").unwrap();
+        let parents: Vec<git2::Commit> = match repo.head() {
+            Ok(head) => vec![head.peel_to_commit().unwrap()],
+            Err(_) => vec![],
+        };
+        let parent_refs: Vec<&git2::Commit> = parents.iter().collect();
+
+        repo.commit(Some("HEAD"), &sig, &sig, message, &tree, &parent_refs)
+            .unwrap()
+    }
+
+    #[test]
+    fn rebase_onto_aborts_on_conflict() {
+        let dir = temp_dir("rebase");
+        let repo = Repository::init(&dir).unwrap();
+
+        let base_oid = commit_file(&repo, "file.txt", "base\n", "base");
+        let local_oid = commit_file(&repo, "file.txt", "local change\n", "local");
+
+        repo.reset(
+            &repo.find_object(base_oid, None).unwrap(),
+            git2::ResetType::Hard,
+            None,
+        )
+        .unwrap();
+        let upstream_oid = commit_file(&repo, "file.txt", "upstream change\n", "upstream");
+
+        repo.reset(
+            &repo.find_object(local_oid, None).unwrap(),
+            git2::ResetType::Hard,
+            None,
+        )
+        .unwrap();
+
+        let local = repo.find_annotated_commit(local_oid).unwrap();
+        let upstream = repo.find_annotated_commit(upstream_oid).unwrap();
+
+        let result = rebase_onto(&repo, &local, &upstream);
+        assert!(result.is_err(), "expected rebase to abort on conflict");
+        assert!(!repo.index().unwrap().has_conflicts());
+    }
+
+    /// Commits a single `name`/`contents` blob straight into a bare repo.
+    fn commit_blob(repo: &Repository, name: &str, contents: &str, message: &str) -> git2::Oid {
+        let blob = repo.blob(contents.as_bytes()).unwrap();
+        let mut builder = repo.treebuilder(None).unwrap();
+        builder
+            .insert(name, blob, git2::FileMode::Blob.into())
+            .unwrap();
+        let tree = repo.find_tree(builder.write().unwrap()).unwrap();
+
+        commit_tree(repo, tree, message)
+    }
+
+    #[test]
+    fn push_branch_reports_non_fast_forward_as_error() {
+        let origin_dir = temp_dir("origin");
+        let origin = Repository::init_bare(&origin_dir).unwrap();
+        commit_blob(&origin, "file.txt", "first\n", "first");
+
+        let local_dir = temp_dir("local");
+        let local = Repository::clone(origin_dir.to_str().unwrap(), &local_dir).unwrap();
+
+        // Simulate someone else pushing directly to origin in the meantime.
+        commit_blob(&origin, "other.txt", "other\n", "someone else's push");
+
+        // Local now pushes a commit that doesn't build on that update, so
+        // the update should be rejected as non-fast-forward.
+        commit_file(&local, "file.txt", "second\n", "second");
+
+        let provider = Provider {
+            name: "test".to_string(),
+            url: origin_dir.to_str().unwrap().to_string(),
+            branch: "master".to_string(),
+            sync_dir: local_dir.to_str().unwrap().to_string(),
+            auth: crate::config::Auth {
+                r#type: AuthType::Public,
+                username: None,
+                password: None,
+                path: None,
+                passphrase: None,
+            },
+            submodules: false,
+            strategy: MergeStrategy::Merge,
+            push: true,
+            depth: None,
+        };
+
+        let result = push_branch(&local, &provider);
+        assert!(result.is_err(), "expected non-fast-forward push to fail");
     }
-    Ok(())
 }
@@ -1,9 +1,12 @@
+use std::sync::Arc;
+
 use clap::Parser;
 use indicatif::MultiProgress;
 
 mod config;
 mod git;
 mod logger;
+mod pool;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -24,15 +27,56 @@ fn main() {
         return;
     }
 
-    let m = MultiProgress::new();
+    let m = Arc::new(MultiProgress::new());
 
     match glone_options.config {
         Some(config) => {
-            for provider in config.get_providers().iter() {
-                log::info!("Starting the cloning for {}", provider.url);
+            log::info!(
+                "Syncing {} provider(s) with {} worker(s)",
+                config.get_providers().len(),
+                config.concurrency
+            );
+
+            let results = pool::sync_all(config.get_providers(), config.concurrency, m);
 
-                git::download(provider, &m)
+            let mut succeeded = 0;
+            let mut diverged = 0;
+            let mut failed = 0;
+            let mut new_commits = 0;
+
+            for result in results {
+                match result.outcome {
+                    git::SyncOutcome::Cloned => {
+                        succeeded += 1;
+                        println!("{}: cloned", result.name);
+                    }
+                    git::SyncOutcome::UpToDate => {
+                        succeeded += 1;
+                        println!("{}: up to date", result.name);
+                    }
+                    git::SyncOutcome::Updated(commits) => {
+                        succeeded += 1;
+                        new_commits += commits.len();
+                        println!("{}: {} new commit(s)", result.name, commits.len());
+                        for commit in &commits {
+                            println!("  {} {} ({})", commit.id, commit.summary, commit.author);
+                        }
+                    }
+                    git::SyncOutcome::Diverged => {
+                        diverged += 1;
+                        println!("{}: diverged (left for manual resolution)", result.name);
+                    }
+                    git::SyncOutcome::Failed(e) => {
+                        failed += 1;
+                        println!("{}: failed ({})", result.name, e);
+                    }
+                }
             }
+
+            println!(
+                "\n{} succeeded, {} diverged, {} failed, {} new commit(s)",
+                succeeded, diverged, failed, new_commits
+            );
         }
         None => todo!(),
     }
@@ -0,0 +1,65 @@
+use std::collections::VecDeque;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use indicatif::MultiProgress;
+
+use crate::config::Provider;
+use crate::git::{self, SyncOutcome};
+
+/// Result of syncing a single provider, paired with its name for reporting.
+pub struct ProviderResult {
+    pub name: String,
+    pub outcome: SyncOutcome,
+}
+
+/// Dispatches `providers` across a bounded pool of `concurrency` worker
+/// threads, each pulling the next provider off a shared queue and syncing it
+/// entirely within its own thread (a `git2::Repository` is not `Send`, so it
+/// can never cross a thread boundary). Only the `Provider` data and the
+/// shared `MultiProgress` are shared between workers.
+pub fn sync_all(
+    providers: &[Provider],
+    concurrency: usize,
+    m: Arc<MultiProgress>,
+) -> Vec<ProviderResult> {
+    let concurrency = concurrency.max(1);
+    let queue = Arc::new(Mutex::new(VecDeque::from(providers.to_vec())));
+    let (tx, rx) = mpsc::channel();
+
+    let workers: Vec<_> = (0..concurrency)
+        .map(|_| {
+            let queue = Arc::clone(&queue);
+            let tx = tx.clone();
+            let m = Arc::clone(&m);
+
+            thread::spawn(move || loop {
+                let provider = match queue.lock().unwrap().pop_front() {
+                    Some(provider) => provider,
+                    None => break,
+                };
+
+                let outcome = git::download(&provider, &m);
+                if tx
+                    .send(ProviderResult {
+                        name: provider.name.clone(),
+                        outcome,
+                    })
+                    .is_err()
+                {
+                    break;
+                }
+            })
+        })
+        .collect();
+
+    drop(tx);
+
+    let results: Vec<ProviderResult> = rx.into_iter().collect();
+
+    for worker in workers {
+        let _ = worker.join();
+    }
+
+    results
+}